@@ -1,26 +1,124 @@
 extern crate clap;
+extern crate futures;
 extern crate rusoto_core;
+extern crate rusoto_credential;
 extern crate rusoto_ec2;
+extern crate rusoto_sts;
 extern crate skim;
 
 use clap::{App, Arg};
-use rusoto_core::Region;
+use futures::Future;
+use rusoto_core::{HttpClient, Region};
+use rusoto_credential::{
+    AutoRefreshingProvider, AwsCredentials, CredentialsError, DefaultCredentialsProvider,
+    ProfileProvider, ProvideAwsCredentials,
+};
 use rusoto_ec2::{DescribeInstancesRequest, Ec2, Ec2Client, Filter, Instance};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
 use skim::{Skim, SkimOptions};
 use std::collections::HashMap;
 use std::default::Default;
 use std::io::Cursor;
+use std::os::unix::process::CommandExt;
+use std::process::{self, Command};
+use std::thread;
+
+// The account/role a region should be queried with: either the default credential chain, a
+// named profile, or a role to assume (optionally on top of a named profile).
+#[derive(Clone)]
+struct Credentials {
+    profile: Option<String>,
+    assume_role: Option<String>,
+}
+
+// The credentials used to look up the role to assume, or to query directly when there's no
+// `--assume-role`: the default provider chain, unless `--profile` names a specific profile.
+enum BaseCredentials {
+    Default(DefaultCredentialsProvider),
+    Profile(ProfileProvider),
+}
+
+impl ProvideAwsCredentials for BaseCredentials {
+    type Future = Box<dyn Future<Item = AwsCredentials, Error = CredentialsError> + Send>;
+
+    fn credentials(&self) -> Self::Future {
+        match self {
+            BaseCredentials::Default(provider) => Box::new(provider.credentials()),
+            BaseCredentials::Profile(provider) => Box::new(provider.credentials()),
+        }
+    }
+}
+
+fn base_credentials(profile: &Option<String>) -> Result<BaseCredentials, String> {
+    match profile {
+        Some(profile) => {
+            let mut provider = ProfileProvider::new().map_err(|err| err.to_string())?;
+            provider.set_profile(profile.clone());
+            Ok(BaseCredentials::Profile(provider))
+        }
+        None => {
+            Ok(BaseCredentials::Default(
+                DefaultCredentialsProvider::new().map_err(|err| err.to_string())?,
+            ))
+        }
+    }
+}
+
+fn ec2_client(region: Region, credentials: &Credentials) -> Result<Ec2Client, String> {
+    match credentials.assume_role {
+        Some(ref role_arn) => {
+            let sts_provider = base_credentials(&credentials.profile)?;
+            let sts_client = StsClient::new_with(
+                HttpClient::new().map_err(|err| err.to_string())?,
+                sts_provider,
+                region.clone(),
+            );
+
+            let assume_role_provider = StsAssumeRoleSessionCredentialsProvider::new(
+                sts_client,
+                role_arn.clone(),
+                "ec2-ip".to_string(),
+                None,
+                None,
+                None,
+                None,
+            );
+
+            let provider = AutoRefreshingProvider::new(assume_role_provider)
+                .map_err(|err| err.to_string())?;
+
+            Ok(Ec2Client::new_with(
+                HttpClient::new().map_err(|err| err.to_string())?,
+                provider,
+                region,
+            ))
+        }
+        None => match credentials.profile {
+            Some(_) => {
+                let provider = base_credentials(&credentials.profile)?;
+
+                Ok(Ec2Client::new_with(
+                    HttpClient::new().map_err(|err| err.to_string())?,
+                    provider,
+                    region,
+                ))
+            }
+            None => Ok(Ec2Client::new(region)),
+        },
+    }
+}
 
 fn get_instances(
     region_name: String,
     filters: Option<Vec<Filter>>,
+    credentials: Credentials,
 ) -> Result<Vec<Instance>, String> {
     let region: Region = match region_name.parse() {
         Ok(region) => region,
         Err(_err) => return Err("Invalid region name".to_string()),
     };
 
-    let client = Ec2Client::new(region);
+    let client = ec2_client(region, &credentials)?;
     let mut region_instances: Vec<Instance> = vec![];
 
     let mut input = DescribeInstancesRequest {
@@ -56,6 +154,208 @@ fn get_instances(
     return Ok(region_instances);
 }
 
+// Picks the address we should connect to: the public IP when `--public-ip` was given, otherwise
+// the private IP. Returns None rather than panicking when the instance doesn't have one.
+fn instance_address(instance: &Instance, public_ip: bool) -> Option<String> {
+    if public_ip {
+        instance.public_ip_address.clone()
+    } else {
+        instance.private_ip_address.clone()
+    }
+}
+
+// Flattens an instance's tags into a lookup map.
+fn instance_tags(instance: &Instance) -> HashMap<String, String> {
+    let mut tag_map = HashMap::new();
+
+    if let Some(ref tags) = instance.tags {
+        for tag in tags {
+            if let Some(ref key) = tag.key {
+                if let Some(ref value) = tag.value {
+                    tag_map.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    tag_map
+}
+
+// The identifier we use for an instance when it's rendered as a host: its Name tag, falling
+// back to the instance id for instances that don't have one.
+fn instance_host_name(instance: &Instance, tags: &HashMap<String, String>) -> String {
+    tags.get("Name")
+        .cloned()
+        .or_else(|| instance.instance_id.clone())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Renders the selected instances as an Ansible-style INI inventory, grouped under `[group_tag
+// value]` sections.
+fn render_inventory(instances: &[&Instance], group_tag: &str, public_ip: bool) -> String {
+    let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+
+    for instance in instances {
+        let tags = instance_tags(instance);
+        let addr = match instance_address(instance, public_ip) {
+            Some(addr) => addr,
+            None => continue,
+        };
+
+        let group = tags
+            .get(group_tag)
+            .cloned()
+            .unwrap_or_else(|| "ungrouped".to_string());
+
+        groups
+            .entry(group)
+            .or_insert_with(Vec::new)
+            .push(format!(
+                "{} ansible_host={}",
+                instance_host_name(instance, &tags),
+                addr
+            ));
+    }
+
+    let mut group_names: Vec<&String> = groups.keys().collect();
+    group_names.sort();
+
+    let mut output = String::new();
+    for group in group_names {
+        output.push_str(format!("[{}]\n", group).as_str());
+
+        for host in &groups[group] {
+            output.push_str(host.as_str());
+            output.push_str("\n");
+        }
+
+        output.push_str("\n");
+    }
+
+    output
+}
+
+// Renders the selected instances as `ssh_config` `Host` blocks.
+fn render_ssh_config(instances: &[&Instance], public_ip: bool, user: &str, identity: Option<&str>) -> String {
+    let mut output = String::new();
+
+    for instance in instances {
+        let tags = instance_tags(instance);
+        let addr = match instance_address(instance, public_ip) {
+            Some(addr) => addr,
+            None => continue,
+        };
+
+        output.push_str(format!("Host {}\n", instance_host_name(instance, &tags)).as_str());
+        output.push_str(format!("  HostName {}\n", addr).as_str());
+        output.push_str(format!("  User {}\n", user).as_str());
+
+        if let Some(identity) = identity {
+            output.push_str(format!("  IdentityFile {}\n", identity).as_str());
+        }
+
+        output.push_str("\n");
+    }
+
+    output
+}
+
+// Resolves a single `{placeholder}` against an instance and its tags. Known fields are the
+// instance id, both IPs, instance type, availability zone and state; anything of the form
+// `tag:KEY` is looked up in the instance's tags. Unknown or missing fields resolve to ""
+// rather than panicking.
+fn resolve_placeholder(instance: &Instance, tags: &HashMap<String, String>, placeholder: &str) -> String {
+    let value = match placeholder {
+        "instance_id" => instance.instance_id.clone(),
+        "private_ip" => instance.private_ip_address.clone(),
+        "public_ip" => instance.public_ip_address.clone(),
+        "instance_type" => instance.instance_type.clone(),
+        "az" => instance
+            .placement
+            .as_ref()
+            .and_then(|placement| placement.availability_zone.clone()),
+        "state" => instance
+            .state
+            .as_ref()
+            .and_then(|state| state.name.clone()),
+        _ => {
+            if placeholder.starts_with("tag:") {
+                tags.get(&placeholder[4..]).cloned()
+            } else {
+                None
+            }
+        }
+    };
+
+    value.unwrap_or_else(|| "".to_string())
+}
+
+// Renders a `--format` template such as `{instance_id} {private_ip} {tag:Name} {az}` against an
+// instance, substituting each `{placeholder}` in turn.
+fn render_template(instance: &Instance, template: &str) -> String {
+    let tags = instance_tags(instance);
+    let mut output = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '{' {
+            output.push(c);
+            continue;
+        }
+
+        let mut placeholder = String::new();
+        while let Some(&next) = chars.peek() {
+            chars.next();
+
+            if next == '}' {
+                break;
+            }
+
+            placeholder.push(next);
+        }
+
+        output.push_str(resolve_placeholder(instance, &tags, &placeholder).as_str());
+    }
+
+    output
+}
+
+// Execs (or, when more than one host is selected, spawns and waits for) the system `ssh` client
+// against each address in turn.
+fn ssh_into(addresses: Vec<String>, user: &str, identity: Option<&str>) {
+    if addresses.is_empty() {
+        eprintln!("no address to SSH into: none of the selected instances have the requested IP");
+        process::exit(1);
+    }
+
+    let build_command = |addr: &str| -> Command {
+        let mut command = Command::new("ssh");
+
+        if let Some(identity) = identity {
+            command.arg("-i").arg(identity);
+        }
+
+        command.arg(format!("{}@{}", user, addr));
+        command
+    };
+
+    if addresses.len() == 1 {
+        let err = build_command(&addresses[0]).exec();
+        panic!("failed to exec ssh: {}", err);
+    }
+
+    for addr in addresses {
+        match build_command(&addr).status() {
+            Ok(status) => {
+                if !status.success() {
+                    eprintln!("ssh {} exited with {}", addr, status);
+                }
+            }
+            Err(err) => eprintln!("failed to run ssh {}: {}", addr, err),
+        }
+    }
+}
+
 pub fn main() {
     let options = App::new("ec2-skim")
         .arg(
@@ -89,8 +389,65 @@ pub fn main() {
                 .takes_value(true)
                 .multiple(true),
         )
+        .arg(
+            Arg::with_name("ssh")
+                .help("SSH into the selected instance(s) instead of printing their address")
+                .long("ssh")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("ssh_user")
+                .help("User to SSH in as (default ec2-user; pass e.g. ubuntu for Ubuntu AMIs)")
+                .long("ssh-user")
+                .takes_value(true)
+                .default_value("ec2-user"),
+        )
+        .arg(
+            Arg::with_name("identity")
+                .help("SSH identity (private key) file to use")
+                .short("i")
+                .long("identity")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .help("Named AWS profile to query instances with")
+                .long("profile")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("assume_role")
+                .help("ARN of a role to assume (via STS) before querying instances")
+                .long("assume-role")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output_format")
+                .help("Render the selected instances as an Ansible inventory or ssh_config instead of printing their address")
+                .long("output-format")
+                .takes_value(true)
+                .possible_values(&["inventory", "ssh-config"]),
+        )
+        .arg(
+            Arg::with_name("group_tag")
+                .help("Tag to group instances by, required when using --output-format inventory")
+                .long("group-tag")
+                .takes_value(true)
+                .required_if("output_format", "inventory"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .help("Template to render each selected instance with, e.g. \"{instance_id} {private_ip} {tag:Name}\"")
+                .long("format")
+                .takes_value(true),
+        )
         .get_matches();
 
+    let credentials = Credentials {
+        profile: options.value_of("profile").map(String::from),
+        assume_role: options.value_of("assume_role").map(String::from),
+    };
+
     let mut all_instances: HashMap<String, Instance> = HashMap::new();
 
     // We allow for multiple filters options. If this happens, we need to fetch the instances for
@@ -135,17 +492,32 @@ pub fn main() {
     }
 
     if let Some(regions) = options.values_of("region") {
+        // Each (region, filter-group) pair is an independent DescribeInstances call, so we fan
+        // them out onto their own thread and join on all of them instead of querying one at a
+        // time. Wall-clock ends up bounded by the slowest single region instead of the sum of
+        // all of them.
+        let mut handles = vec![];
+
         for region in regions {
             for filters in filter_groups.clone() {
-                let instances = match get_instances(region.to_string(), Some(filters.clone())) {
-                    Ok(instances) => instances.clone(),
-                    Err(err) => panic!(err),
-                };
+                let region = region.to_string();
+                let credentials = credentials.clone();
 
-                for instance in instances {
-                    if let Some(instance_id) = instance.clone().instance_id {
-                        all_instances.insert(instance_id, instance);
-                    }
+                handles.push(thread::spawn(move || {
+                    get_instances(region, Some(filters), credentials)
+                }));
+            }
+        }
+
+        for handle in handles {
+            let instances = match handle.join().unwrap() {
+                Ok(instances) => instances,
+                Err(err) => panic!(err),
+            };
+
+            for instance in instances {
+                if let Some(instance_id) = instance.clone().instance_id {
+                    all_instances.insert(instance_id, instance);
                 }
             }
         }
@@ -165,21 +537,11 @@ pub fn main() {
             skim_input.push_str(format!("{:19}: ", instance_id).as_str());
         }
 
-        if let Some(tags) = instance.clone().tags {
-            let mut tag_map: HashMap<String, String> = HashMap::new();
-
-            for tag in tags {
-                if let Some(key) = tag.key {
-                    if let Some(value) = tag.value {
-                        tag_map.insert(key, value);
-                    }
-                }
-            }
+        let tag_map = instance_tags(instance);
 
-            for display_tag in display_tags.clone() {
-                if let Some(value) = tag_map.get(&display_tag) {
-                    skim_input.push_str(format!("{}={} ", &display_tag, value).as_str());
-                }
+        for display_tag in display_tags.clone() {
+            if let Some(value) = tag_map.get(&display_tag) {
+                skim_input.push_str(format!("{}={} ", &display_tag, value).as_str());
             }
         }
 
@@ -192,13 +554,58 @@ pub fn main() {
         .map(|out| out.selected_items)
         .unwrap_or_else(|| Vec::new());
 
-    for item in selected_items.iter() {
-        if let Some(instance) = instances.clone().nth(item.get_index()) {
-            if options.is_present("public_ip") {
-                print!("{}", instance.clone().public_ip_address.unwrap());
-            } else {
-                print!("{}", instance.clone().private_ip_address.unwrap());
-            }
+    let selected_instances: Vec<&Instance> = selected_items
+        .iter()
+        .filter_map(|item| instances.clone().nth(item.get_index()))
+        .collect();
+
+    if let Some(output_format) = options.value_of("output_format") {
+        let rendered = match output_format {
+            "inventory" => render_inventory(
+                &selected_instances,
+                options.value_of("group_tag").unwrap(),
+                options.is_present("public_ip"),
+            ),
+            "ssh-config" => render_ssh_config(
+                &selected_instances,
+                options.is_present("public_ip"),
+                options.value_of("ssh_user").unwrap(),
+                options.value_of("identity"),
+            ),
+            _ => unreachable!(),
+        };
+
+        print!("{}", rendered);
+
+        return;
+    }
+
+    if options.is_present("ssh") {
+        let addresses: Vec<String> = selected_instances
+            .iter()
+            .filter_map(|instance| instance_address(instance, options.is_present("public_ip")))
+            .collect();
+
+        ssh_into(
+            addresses,
+            options.value_of("ssh_user").unwrap(),
+            options.value_of("identity"),
+        );
+
+        return;
+    }
+
+    if let Some(template) = options.value_of("format") {
+        for instance in selected_instances {
+            println!("{}", render_template(instance, template));
+        }
+
+        return;
+    }
+
+    for instance in selected_instances {
+        if let Some(addr) = instance_address(instance, options.is_present("public_ip")) {
+            print!("{}", addr);
         }
     }
 }